@@ -1,20 +1,31 @@
-use std::{fs::File, io::BufWriter};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufReader, BufWriter},
+};
 
-use geo_types::Point;
-use gpx::{write, Gpx, Track, TrackSegment, Waypoint};
+use clap::Parser;
+use geo_types::{Coord, Geometry, LineString, Point, Rect};
+use geozero::{ColumnValue, FeatureProcessor, GeozeroDatasource, GeozeroGeometry, PropertyProcessor};
+use gpx::{write, Gpx, Metadata, Track, TrackSegment, Waypoint};
 use proj::Proj;
+use rstar::{primitives::GeomWithData, RTree};
 use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 
-// This was generated with https://app.quicktype.io/
-#[derive(Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+// Skeleton of an ArcGIS FeatureServer query response (originally generated with
+// https://app.quicktype.io/). Every field defaults when absent (`features` to an
+// empty list) so the tool deserializes an arbitrary line/point layer, not just
+// the fully-populated MCM one.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
 struct MarineDataLayers {
-    object_id_field_name: String,
-    unique_id_field: UniqueIdField,
-    global_id_field_name: String,
-    geometry_properties: GeometryProperties,
-    geometry_type: String,
-    spatial_reference: SpatialReference,
+    object_id_field_name: Option<String>,
+    unique_id_field: Option<UniqueIdField>,
+    global_id_field_name: Option<String>,
+    geometry_properties: Option<GeometryProperties>,
+    geometry_type: Option<String>,
+    spatial_reference: Option<SpatialReference>,
     fields: Vec<Field>,
     features: Vec<Feature>,
 }
@@ -22,21 +33,23 @@ struct MarineDataLayers {
 #[derive(Serialize, Deserialize)]
 struct Feature {
     attributes: Attributes,
-    geometry: Geometry,
+    geometry: EsriGeometry,
 }
 
 #[derive(Serialize, Deserialize)]
-#[serde(rename_all = "PascalCase")]
 struct Attributes {
-    #[serde(rename = "OBJECTID")]
-    objectid: i64,
-    course: String,
-    #[serde(rename = "Shape__Length")]
-    shape_length: f64,
+    /// The course this line belongs to; optional so the tool tolerates layers
+    /// that don't carry this field at all.
+    #[serde(rename = "Course", alias = "course", default)]
+    course: Option<String>,
+    /// Any remaining attributes, captured verbatim so an arbitrary ArcGIS line
+    /// layer round-trips without a bespoke schema.
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
 }
 
 #[derive(Serialize, Deserialize)]
-struct Geometry {
+struct EsriGeometry {
     paths: Vec<Vec<[f64; 2]>>,
 }
 
@@ -46,8 +59,10 @@ struct Field {
     name: String,
     #[serde(rename = "type")]
     field_type: String,
-    alias: String,
-    sql_type: String,
+    #[serde(default)]
+    alias: Option<String>,
+    #[serde(default)]
+    sql_type: Option<String>,
     domain: Option<serde_json::Value>,
     default_value: Option<serde_json::Value>,
     length: Option<i64>,
@@ -56,34 +71,288 @@ struct Field {
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct GeometryProperties {
-    shape_length_field_name: String,
-    units: String,
+    #[serde(default)]
+    shape_length_field_name: Option<String>,
+    #[serde(default)]
+    units: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct SpatialReference {
-    wkid: i64,
-    latest_wkid: i64,
+    #[serde(default)]
+    wkid: Option<i64>,
+    #[serde(default)]
+    latest_wkid: Option<i64>,
 }
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct UniqueIdField {
-    name: String,
-    is_system_maintained: bool,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    is_system_maintained: Option<bool>,
+}
+
+/// An endpoint of a raw path, tagged with the path it belongs to and whether
+/// it is that path's first (`true`) or last (`false`) vertex.
+type Endpoint = GeomWithData<[f64; 2], (usize, bool)>;
+
+/// Tolerance, in projected (Web Mercator) units, within which two path
+/// endpoints are treated as the same junction. A few meters comfortably
+/// absorbs the rounding in the published coordinates without bridging
+/// genuinely separate spurs.
+const STITCH_TOLERANCE: f64 = 5.0;
+
+/// The two endpoints of a fragment, `[first_vertex, last_vertex]`.
+type FragmentEnds = [[f64; 2]; 2];
+
+/// Disjoint-set root of `x`, with path compression.
+fn find(parent: &mut [usize], x: usize) -> usize {
+    let mut root = x;
+    while parent[root] != root {
+        root = parent[root];
+    }
+    let mut cur = x;
+    while parent[cur] != root {
+        let next = parent[cur];
+        parent[cur] = root;
+        cur = next;
+    }
+    root
+}
+
+/// Order and orient a layer's raw `paths` into one continuous course.
+///
+/// ArcGIS hands back the course as an unordered bag of polyline fragments, so
+/// we stitch them by walking an endpoint index instead of relying on
+/// hand-tuned indices. Each fragment contributes its two endpoints to an
+/// `RTree`, and fragments whose endpoints coincide within [`STITCH_TOLERANCE`]
+/// are grouped into connected components with a union-find.
+///
+/// The MCM course is not a simple chain: it has out-and-back legs where a
+/// single ArcGIS fragment is run in both directions (the hand-tuned baseline
+/// traversed fragments twice, forward then reversed). A plain greedy walk that
+/// retires each fragment after one use dead-ends at the first turnaround, so we
+/// instead depth-first search the component starting from a dangling end,
+/// preferring unused fragments but allowing a fragment to be re-traversed (up
+/// to twice) to escape a spur. The walk that covers the most of the component
+/// wins; its fragments are returned in course order, each oriented so its first
+/// vertex meets the previous one's last. Any fragment left uncovered is
+/// reported as leftover.
+fn stitch_paths(paths: &[Vec<[f64; 2]>]) -> (Vec<Vec<[f64; 2]>>, Vec<usize>) {
+    let tol2 = STITCH_TOLERANCE * STITCH_TOLERANCE;
+
+    // Index every usable fragment's endpoints.
+    let mut endpoints = Vec::with_capacity(paths.len() * 2);
+    let mut ends: Vec<Option<FragmentEnds>> = vec![None; paths.len()];
+    for (id, path) in paths.iter().enumerate() {
+        if path.len() < 2 {
+            continue;
+        }
+        let first = path[0];
+        let last = *path.last().unwrap();
+        ends[id] = Some([first, last]);
+        endpoints.push(Endpoint::new(first, (id, true)));
+        endpoints.push(Endpoint::new(last, (id, false)));
+    }
+    let tree = RTree::bulk_load(endpoints);
+
+    // Group fragments that share a junction into connected components.
+    let mut parent: Vec<usize> = (0..paths.len()).collect();
+    for (id, end) in ends.iter().enumerate() {
+        let Some(end) = end else { continue };
+        for coord in end {
+            for neighbour in tree.locate_within_distance(*coord, tol2) {
+                let other = neighbour.data.0;
+                let (a, b) = (find(&mut parent, id), find(&mut parent, other));
+                if a != b {
+                    parent[a] = b;
+                }
+            }
+        }
+    }
+
+    // Prefer to start at a dangling end (an endpoint that no other fragment
+    // touches) so a point-to-point course runs the right way round; fall back
+    // to the first usable fragment for a closed loop.
+    let mut start = None;
+    'search: for (id, end) in ends.iter().enumerate() {
+        let Some(end) = end else { continue };
+        for (coord, is_start) in [(end[0], true), (end[1], false)] {
+            let dangling = !tree
+                .locate_within_distance(coord, tol2)
+                .any(|e| e.data.0 != id);
+            if dangling {
+                start = Some((id, coord, is_start));
+                break 'search;
+            }
+        }
+    }
+    let (start_id, start_coord, _) =
+        match start.or_else(|| ends.iter().position(|e| e.is_some()).map(|id| {
+            let end = ends[id].unwrap();
+            (id, end[0], true)
+        })) {
+            Some(s) => s,
+            None => return (Vec::new(), Vec::new()),
+        };
+
+    let component = find(&mut parent, start_id);
+    let target = ends
+        .iter()
+        .enumerate()
+        .filter(|(id, e)| e.is_some() && find(&mut parent, *id) == component)
+        .count();
+
+    // Depth-first search for the walk covering the most of the component,
+    // preferring unused fragments and doubling back through a spur only when no
+    // fresh fragment adjoins the current open end.
+    let mut use_count = vec![0u8; paths.len()];
+    let mut acc: Vec<(usize, bool)> = Vec::new();
+    let mut best: Vec<(usize, bool)> = Vec::new();
+    let mut best_covered = 0usize;
+    let mut remaining = target;
+    // Iterative DFS over a stack of candidate frames, one per visited open end,
+    // to avoid recursing the full depth of a long course. Each frame owns the
+    // ordered candidate list it is still iterating.
+    let mut frames: Vec<Vec<(usize, bool, [f64; 2])>> =
+        vec![candidates(&ends, &tree, &start_coord, tol2, &parent, component, &use_count)];
+
+    while !frames.is_empty() {
+        if target - remaining > best_covered {
+            best = acc.clone();
+            best_covered = target - remaining;
+        }
+        if remaining == 0 {
+            break;
+        }
+        if let Some((id, is_start, open_end)) = frames.last_mut().unwrap().pop() {
+            if use_count[id] >= 2 {
+                continue;
+            }
+            if use_count[id] == 0 {
+                remaining -= 1;
+            }
+            use_count[id] += 1;
+            acc.push((id, !is_start));
+            frames.push(candidates(
+                &ends, &tree, &open_end, tol2, &parent, component, &use_count,
+            ));
+        } else {
+            // Exhausted this frame; backtrack.
+            frames.pop();
+            if let Some((id, _)) = acc.pop() {
+                use_count[id] -= 1;
+                if use_count[id] == 0 {
+                    remaining += 1;
+                }
+            }
+        }
+    }
+
+    // Re-play the winning walk into oriented fragments.
+    let mut ordered = Vec::with_capacity(best.len());
+    let mut covered = vec![false; paths.len()];
+    for (id, reversed) in &best {
+        covered[*id] = true;
+        let path = &paths[*id];
+        ordered.push(if *reversed {
+            path.iter().rev().copied().collect()
+        } else {
+            path.clone()
+        });
+    }
+
+    let leftover: Vec<usize> = (0..paths.len())
+        .filter(|&id| paths[id].len() >= 2 && !covered[id])
+        .collect();
+    if !leftover.is_empty() {
+        eprintln!(
+            "warning: {} path(s) left unused by stitching: {:?}",
+            leftover.len(),
+            leftover
+        );
+    }
+
+    (ordered, leftover)
+}
+
+/// Fragments in `component` that adjoin `point`, as `(fragment, is_start,
+/// open_end)`, ordered so the search pops unused fragments first. `is_start`
+/// says the near endpoint is the fragment's first vertex; `open_end` is the
+/// vertex the walk continues from after traversing it.
+fn candidates(
+    ends: &[Option<FragmentEnds>],
+    tree: &RTree<Endpoint>,
+    point: &[f64; 2],
+    tol2: f64,
+    parent: &[usize],
+    component: usize,
+    use_count: &[u8],
+) -> Vec<(usize, bool, [f64; 2])> {
+    // A read-only root chase; `find`'s path compression needs `&mut`.
+    let root = |mut x: usize| {
+        while parent[x] != x {
+            x = parent[x];
+        }
+        x
+    };
+    let mut cands: Vec<(usize, bool, [f64; 2])> = tree
+        .locate_within_distance(*point, tol2)
+        .filter(|e| root(e.data.0) == component && use_count[e.data.0] < 2)
+        .map(|e| {
+            let (id, is_start) = e.data;
+            let fragment = ends[id].unwrap();
+            let open_end = if is_start { fragment[1] } else { fragment[0] };
+            (id, is_start, open_end)
+        })
+        .collect();
+    // Popped from the back, so place unused fragments last → tried first.
+    cands.sort_by_key(|&(id, _, _)| std::cmp::Reverse(use_count[id]));
+    cands
+}
+
+/// Earth's mean radius in metres, matching the sphere the haversine formula
+/// assumes.
+const EARTH_RADIUS_M: f64 = 6_371_000.0;
+
+/// Great-circle distance in metres between two WGS84 waypoints.
+fn haversine_m(a: &Waypoint, b: &Waypoint) -> f64 {
+    let (lng1, lat1) = (a.point().x(), a.point().y());
+    let (lng2, lat2) = (b.point().x(), b.point().y());
+    let (phi1, phi2) = (lat1.to_radians(), lat2.to_radians());
+    let dphi = (lat2 - lat1).to_radians();
+    let dlambda = (lng2 - lng1).to_radians();
+    let h = (dphi / 2.0).sin().powi(2)
+        + phi1.cos() * phi2.cos() * (dlambda / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * h.sqrt().asin()
+}
+
+/// Axis-aligned WGS84 bounding box of a run of waypoints, or `None` if empty.
+fn course_bounds(points: &[Waypoint]) -> Option<Rect<f64>> {
+    let mut iter = points.iter();
+    let first = iter.next()?.point();
+    let (mut min_x, mut min_y) = (first.x(), first.y());
+    let (mut max_x, mut max_y) = (min_x, min_y);
+    for wp in iter {
+        let p = wp.point();
+        min_x = min_x.min(p.x());
+        min_y = min_y.min(p.y());
+        max_x = max_x.max(p.x());
+        max_y = max_y.max(p.y());
+    }
+    Some(Rect::new(
+        Coord { x: min_x, y: min_y },
+        Coord { x: max_x, y: max_y },
+    ))
 }
 
 fn write_segment(
     converter: &Proj,
     segment: Vec<[f64; 2]>,
-    reversed: bool,
 ) -> Result<Vec<Waypoint>, Box<dyn std::error::Error>> {
-    let segment = if reversed {
-        segment.into_iter().rev().collect()
-    } else {
-        segment
-    };
     let mut track_points: Vec<Waypoint> = Vec::with_capacity(segment.len());
 
     for point in segment {
@@ -96,13 +365,271 @@ fn write_segment(
     Ok(track_points)
 }
 
+/// A course vertex in the deviation index, keyed by its WGS84 position and
+/// tagged with its position along the course so the adjacent segments can be
+/// recovered from a nearest-neighbour hit.
+type CourseVertex = GeomWithData<[f64; 2], usize>;
+
+/// Distance in metres from point `p` to the segment `a`–`b`, all `[lon, lat]`.
+///
+/// The three points are projected into a local equirectangular frame (metres,
+/// centred on `p`'s latitude) where a straight course segment stays straight,
+/// then `p` is projected onto the segment and clamped to its endpoints.
+fn point_segment_dist_m(p: [f64; 2], a: [f64; 2], b: [f64; 2]) -> f64 {
+    let k = EARTH_RADIUS_M * std::f64::consts::PI / 180.0;
+    let coslat = p[1].to_radians().cos();
+    let project = |c: [f64; 2]| (c[0] * coslat * k, c[1] * k);
+    let (px, py) = project(p);
+    let (ax, ay) = project(a);
+    let (bx, by) = project(b);
+
+    let (dx, dy) = (bx - ax, by - ay);
+    let len2 = dx * dx + dy * dy;
+    let t = if len2 == 0.0 {
+        0.0
+    } else {
+        (((px - ax) * dx + (py - ay) * dy) / len2).clamp(0.0, 1.0)
+    };
+    let (cx, cy) = (ax + t * dx, ay + t * dy);
+    ((px - cx).powi(2) + (py - cy).powi(2)).sqrt()
+}
+
+/// Total length in kilometres of every segment in a track.
+fn track_length_km(track: &Track) -> f64 {
+    let meters: f64 = track
+        .segments
+        .iter()
+        .flat_map(|s| s.points.windows(2).map(|w| haversine_m(&w[0], &w[1])))
+        .sum();
+    meters / 1000.0
+}
+
+/// Index the official course's vertices so recorded traces can be measured
+/// against it with nearest-neighbour queries. Returns both the index and the
+/// course vertices in order, which the segment-distance query needs.
+fn build_course_index(line: &LineString<f64>) -> (RTree<CourseVertex>, Vec<[f64; 2]>) {
+    let coords: Vec<[f64; 2]> = line.coords().map(|c| [c.x, c.y]).collect();
+    let vertices = coords
+        .iter()
+        .enumerate()
+        .map(|(i, &c)| CourseVertex::new(c, i))
+        .collect();
+    (RTree::bulk_load(vertices), coords)
+}
+
+/// How many nearest course vertices to probe per recorded waypoint when
+/// measuring deviation. The MCM course overlaps itself (out-and-back and
+/// parallel legs), so the single closest vertex may belong to a different leg
+/// than the one the runner was on; probing a small neighbourhood lets the true
+/// leg's segment win the minimum.
+const DEVIATION_NEIGHBORS: usize = 8;
+
+/// Maximum and mean deviation (in metres) of a recorded track from the course.
+///
+/// For each recorded waypoint we take the [`DEVIATION_NEIGHBORS`] nearest
+/// course vertices and measure the point-to-segment distance to every segment
+/// incident to them, keeping the smallest. Measuring against segments rather
+/// than bare vertices avoids inflating the deviation of a point beside a long
+/// segment's midpoint, and probing several vertices keeps a self-overlapping
+/// course from snapping the measurement onto the wrong leg.
+fn deviation_from_course(
+    track: &Track,
+    index: &RTree<CourseVertex>,
+    coords: &[[f64; 2]],
+) -> (f64, f64) {
+    let (mut max, mut sum, mut n) = (0.0_f64, 0.0_f64, 0usize);
+    for segment in &track.segments {
+        for wp in &segment.points {
+            let point = wp.point();
+            let p = [point.x(), point.y()];
+            // The segments incident to vertex `i` are (i-1, i) and (i, i+1);
+            // take the nearest such segment across the probed neighbourhood.
+            let mut d = f64::INFINITY;
+            for vertex in index.nearest_neighbor_iter(&p).take(DEVIATION_NEIGHBORS) {
+                let i = vertex.data;
+                if i > 0 {
+                    d = d.min(point_segment_dist_m(p, coords[i - 1], coords[i]));
+                }
+                if i + 1 < coords.len() {
+                    d = d.min(point_segment_dist_m(p, coords[i], coords[i + 1]));
+                }
+            }
+            if d.is_finite() {
+                max = max.max(d);
+                sum += d;
+                n += 1;
+            }
+        }
+    }
+    (max, if n > 0 { sum / n as f64 } else { 0.0 })
+}
+
+/// A batch of recorded points exported by the Overland GPS logger.
+#[derive(Serialize, Deserialize)]
+struct OverlandBatch {
+    locations: Vec<OverlandFeature>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OverlandFeature {
+    geometry: OverlandGeometry,
+    properties: OverlandProperties,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OverlandGeometry {
+    /// `[lon, lat]`, already WGS84.
+    coordinates: [f64; 2],
+}
+
+#[derive(Serialize, Deserialize)]
+struct OverlandProperties {
+    timestamp: Option<String>,
+    altitude: Option<f64>,
+    speed: Option<f64>,
+}
+
+/// Read an Overland location batch into a GPX [`Track`] of recorded waypoints.
+///
+/// Overland coordinates are already WGS84, so they skip the [`Proj`]
+/// conversion; each feature carries its `timestamp` into the waypoint's `time`
+/// and its `altitude` into `elevation`.
+fn overland_track(path: &str) -> Result<Track, Box<dyn std::error::Error>> {
+    let batch: OverlandBatch = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+
+    let mut segment = TrackSegment::default();
+    for location in batch.locations {
+        let [lng, lat] = location.geometry.coordinates;
+        let mut waypoint = Waypoint::new(Point::new(lng, lat));
+        waypoint.elevation = location.properties.altitude;
+        if let Some(ts) = &location.properties.timestamp {
+            waypoint.time = Some(OffsetDateTime::parse(ts, &Rfc3339)?.into());
+        }
+        segment.points.push(waypoint);
+    }
+
+    let mut track = Track::default();
+    track.description = Some("Recorded trace (Overland)".to_string());
+    track.segments.push(segment);
+    Ok(track)
+}
+
+/// Output container for the converted course.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Format {
+    Gpx,
+    Geojson,
+    Gpkg,
+}
+
+/// The stitched course as a single geometry plus the properties worth carrying
+/// across every output format. Implementing [`GeozeroDatasource`] lets the same
+/// value drive any `geozero` writer (GeoJSON, GeoPackage, ...) so the geometry
+/// pipeline no longer knows anything about GPX.
+struct CourseFeature {
+    geometry: Geometry<f64>,
+    course: String,
+    length_km: f64,
+}
+
+impl GeozeroDatasource for CourseFeature {
+    fn process<P: FeatureProcessor>(&mut self, proc: &mut P) -> geozero::error::Result<()> {
+        proc.dataset_begin(None)?;
+        proc.feature_begin(0)?;
+        proc.properties_begin()?;
+        proc.property(0, "course", &ColumnValue::String(&self.course))?;
+        proc.property(1, "length_km", &ColumnValue::Double(self.length_km))?;
+        proc.properties_end()?;
+        proc.geometry_begin()?;
+        self.geometry.process_geom(proc)?;
+        proc.geometry_end()?;
+        proc.feature_end(0)?;
+        proc.dataset_end()?;
+        Ok(())
+    }
+}
+
+/// Write a course feature as a GeoJSON `FeatureCollection`.
+fn write_geojson(path: &str, mut feature: CourseFeature) -> Result<(), Box<dyn std::error::Error>> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let mut writer = geozero::geojson::GeoJsonWriter::new(&mut out);
+    feature.process(&mut writer)?;
+    Ok(())
+}
+
+/// Write a course feature into a GeoPackage layer named `course`.
+fn write_gpkg(path: &str, mut feature: CourseFeature) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writer = geozero::gpkg::GpkgWriter::new(path, "course")?;
+    feature.process(&mut writer)?;
+    Ok(())
+}
+
+/// Default ArcGIS FeatureServer query for the Marine Corps Marathon layer.
+const DEFAULT_URL: &str = "https://services3.arcgis.com/uriB49wQuOhO1ZVZ/arcgis/\
+                           rest/services/Marine_Corps_Marathon_Map_WFL1/\
+                           FeatureServer/4/query?where=1%3D1&outFields=*&f=json";
+
+/// Fetch an ArcGIS line layer and convert one course into a GPX track, a
+/// GeoJSON `FeatureCollection` or a GeoPackage layer.
+#[derive(Parser)]
+#[command(about = "Stitch an ArcGIS line course into a GPX, GeoJSON or GeoPackage file")]
+struct Cli {
+    /// FeatureServer query URL returning Esri JSON.
+    #[arg(long, default_value = DEFAULT_URL)]
+    url: String,
+    /// Value of the `course` attribute to select.
+    #[arg(long, default_value = "MCM")]
+    course: String,
+    /// Source CRS of the layer geometry.
+    #[arg(long, default_value = "EPSG:3857")]
+    from_crs: String,
+    /// Target CRS for the written coordinates.
+    #[arg(long, default_value = "EPSG:4326")]
+    to_crs: String,
+    /// Output filename.
+    #[arg(long, default_value = "mcm.gpx")]
+    out: String,
+    /// Output format.
+    #[arg(long, value_enum, default_value_t = Format::Gpx)]
+    format: Format,
+    /// Append an Overland location batch (JSON) as a separate recorded track.
+    #[arg(long)]
+    overland: Option<String>,
+    /// Existing GPX file(s) to load, measure, and compare against the course.
+    #[arg(long)]
+    compare: Vec<String>,
+    /// Write all compared tracks plus the stitched course into `--out`.
+    #[arg(long)]
+    merge: bool,
+    /// List the distinct `course` values and their feature counts, then exit.
+    #[arg(long)]
+    list_courses: bool,
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let url = "https://services3.arcgis.com/uriB49wQuOhO1ZVZ/arcgis/rest/\
-               services/Marine_Corps_Marathon_Map_WFL1/FeatureServer/4/query?\
-               where=1%3D1&outFields=*&f=json";
+    let cli = Cli::parse();
 
-    let response: MarineDataLayers = reqwest::get(url).await?.json().await?;
+    let response: MarineDataLayers = reqwest::get(&cli.url).await?.json().await?;
+
+    // `--list-courses` is a discovery mode: tally the `course` attribute across
+    // every feature and print the distinct values so the user knows what to
+    // pass to `--course`.
+    if cli.list_courses {
+        let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+        for feature in &response.features {
+            let key = feature
+                .attributes
+                .course
+                .clone()
+                .unwrap_or_else(|| "<none>".to_string());
+            *counts.entry(key).or_default() += 1;
+        }
+        for (course, count) in counts {
+            println!("{course}\t{count}");
+        }
+        return Ok(());
+    }
 
     // Initialize a GPX object
     let mut gpx = Gpx {
@@ -110,66 +637,113 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         ..Default::default()
     };
 
-    // Convert from Web Mercator to WGS84
-    let from = "EPSG:3857";
-    let to = "EPSG:4326";
-    let converter = Proj::new_known_crs(from, to, None)?;
+    // Convert from the source CRS to the target CRS
+    let converter = Proj::new_known_crs(&cli.from_crs, &cli.to_crs, None)?;
 
     let mut track = Track::default();
     let mut track_segment = TrackSegment::default();
+    let mut unstitched = 0usize;
     for feature in response.features {
-        if feature.attributes.course == "MCM" {
-            let paths = feature.geometry.paths;
-
-            // Define a closure to handle segment processing
-            let mut process_segment = |index: usize, reversed: bool| {
-                if let Some(path) = paths.get(index) {
-                    if let Ok(segment) = write_segment(&converter, path.clone(), reversed) {
-                        track_segment.points.extend(segment);
-                    } else {
-                        eprintln!("Failed to process segment at index {}", index);
-                    }
-                } else {
-                    eprintln!("Invalid path index: {}", index);
+        if feature.attributes.course.as_deref() == Some(cli.course.as_str()) {
+            // Stitch the raw fragments into one ordered, correctly oriented
+            // course, then project each segment into the target CRS in turn.
+            let (ordered, leftover) = stitch_paths(&feature.geometry.paths);
+            unstitched += leftover.len();
+            for segment in ordered {
+                match write_segment(&converter, segment) {
+                    Ok(points) => track_segment.points.extend(points),
+                    Err(err) => eprintln!("Failed to process segment: {}", err),
                 }
-            };
-
-            process_segment(9, true);
-            process_segment(11, false);
-            process_segment(13, false);
-            process_segment(14, false);
-            process_segment(15, false);
-            process_segment(17, false);
-            process_segment(18, false);
-            process_segment(20, false);
-            process_segment(21, false);
-            process_segment(20, true);
-            process_segment(19, false);
-            process_segment(17, true);
-            process_segment(16, false);
-            process_segment(12, false);
-            process_segment(6, false);
-            process_segment(8, false);
-            process_segment(7, false);
-            process_segment(5, false);
-            process_segment(3, false);
-            process_segment(1, false);
-            process_segment(0, true);
-            process_segment(1, true);
-            process_segment(2, false);
-            process_segment(4, false);
-            process_segment(10, false);
+            }
+        }
+    }
+    // Summarise the stitched course before writing: a bounding box for the
+    // GPX metadata and a total length from consecutive-point haversine hops,
+    // so the output can be sanity-checked against the expected ~42.2 km.
+    let total_m: f64 = track_segment
+        .points
+        .windows(2)
+        .map(|w| haversine_m(&w[0], &w[1]))
+        .sum();
+    let total_km = total_m / 1000.0;
+    let total_mi = total_m / 1609.344;
+    println!("Course length: {:.2} km ({:.2} mi)", total_km, total_mi);
+    track.description = Some(format!("{:.2} km ({:.2} mi)", total_km, total_mi));
+    // The printed length only means something if every fragment was stitched;
+    // flag a short, incomplete course rather than letting it look authoritative.
+    if unstitched > 0 {
+        eprintln!(
+            "warning: course is incomplete — {} fragment(s) could not be stitched, \
+             so the length above is short of the true distance",
+            unstitched
+        );
+    }
+
+    // A format-neutral view of the stitched geometry for the `geozero` writers.
+    let line: LineString<f64> = track_segment
+        .points
+        .iter()
+        .map(|w| (w.point().x(), w.point().y()))
+        .collect();
+
+    // The vector formats are written from `line` through the `geozero` writers;
+    // `line` is built from the converted GPX waypoints above, and the GPX
+    // `Track`, its metadata and any extra tracks are not written for them.
+    if cli.format != Format::Gpx {
+        let feature = CourseFeature {
+            geometry: Geometry::LineString(line),
+            course: cli.course.clone(),
+            length_km: total_km,
+        };
+        match cli.format {
+            Format::Geojson => write_geojson(&cli.out, feature)?,
+            Format::Gpkg => write_gpkg(&cli.out, feature)?,
+            Format::Gpx => unreachable!(),
         }
+        println!("Done! File written to {}", cli.out);
+        return Ok(());
+    }
+
+    if let Some(bounds) = course_bounds(&track_segment.points) {
+        gpx.metadata = Some(Metadata {
+            bounds: Some(bounds),
+            ..Default::default()
+        });
     }
+
     track.segments.push(track_segment);
     gpx.tracks.push(track);
 
-    let file_name = "mcm.gpx";
-    let file = File::create(file_name)?;
+    // Append a recorded trace alongside the official stitched course.
+    if let Some(overland) = &cli.overland {
+        gpx.tracks.push(overland_track(overland)?);
+    }
+
+    // Load any existing GPX traces, report their length and how far they stray
+    // from the official course, and fold them into the output when merging.
+    if !cli.compare.is_empty() {
+        let (course_index, course_coords) = build_course_index(&line);
+        println!("official course: {:.2} km", total_km);
+        for path in &cli.compare {
+            let loaded = gpx::read(BufReader::new(File::open(path)?))?;
+            for (i, trk) in loaded.tracks.iter().enumerate() {
+                let name = trk.name.clone().unwrap_or_else(|| format!("track {i}"));
+                println!("{path} [{name}]: {:.2} km", track_length_km(trk));
+                let (max_dev, mean_dev) =
+                    deviation_from_course(trk, &course_index, &course_coords);
+                println!("  deviation from course: max {max_dev:.1} m, mean {mean_dev:.1} m");
+                if cli.merge {
+                    gpx.tracks.push(trk.clone());
+                }
+            }
+        }
+    }
+
+    let file = File::create(&cli.out)?;
     let writer = BufWriter::new(file);
     write(&gpx, writer)?;
 
-    println!("Done! File written to {}", file_name);
+    println!("Done! File written to {}", cli.out);
 
     Ok(())
 }